@@ -0,0 +1,204 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The contexts that the style system hands traversal implementations
+//! as it walks the DOM, plus the thread-local scratch space the
+//! parallel traversal keeps one of per worker thread.
+
+use dom::TElement;
+use rayon;
+use std::cell::{RefCell, RefMut};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Add;
+use traversal::DomTraversal;
+
+/// Knobs controlling the style system's behavior that don't belong to
+/// any one traversal.
+#[derive(Clone, Default)]
+pub struct StyleSystemOptions {
+    /// Whether to dump aggregate traversal statistics to stdout when a
+    /// traversal finishes.
+    pub dump_style_statistics: bool,
+
+    /// Whether `top_down_dom` should reorder the children it discovers
+    /// by `TElement::style_sharing_key` before flushing them, so cousins
+    /// likely to share style end up adjacent (and thus more likely to
+    /// land on the same thread). Off by default so it can be A/B
+    /// measured via `dump_style_statistics`.
+    pub regroup_cousins_by_sharing_key: bool,
+}
+
+/// DOM-independent state shared by every thread participating in a
+/// traversal.
+pub struct SharedStyleContext {
+    /// The style system options in effect for this traversal.
+    pub options: StyleSystemOptions,
+}
+
+/// Aggregate statistics about a traversal, either for a single thread or
+/// (via `+`) summed across every thread that took part.
+#[derive(Clone, Default)]
+pub struct TraversalStatistics {
+    /// The number of elements traversed.
+    pub elements_traversed: u32,
+    /// The number of elements actually restyled.
+    pub elements_styled: u32,
+    /// The number of times the style sharing cache produced a usable hit.
+    pub sharing_cache_hits: u32,
+    /// The number of times the style sharing cache was consulted and
+    /// came back empty.
+    pub sharing_cache_misses: u32,
+    /// Wall-clock time the traversal took, in milliseconds.
+    pub traversal_time_ms: f64,
+}
+
+impl TraversalStatistics {
+    /// The fraction of style sharing cache lookups that hit, in `[0, 1]`.
+    /// Returns `0.0` if the cache was never consulted.
+    pub fn sharing_cache_hit_ratio(&self) -> f32 {
+        let total = self.sharing_cache_hits + self.sharing_cache_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.sharing_cache_hits as f32 / total as f32
+        }
+    }
+
+    /// Whether this traversal touched enough elements that it's worth
+    /// printing statistics for.
+    pub fn is_large_traversal(&self) -> bool {
+        self.elements_traversed > 50
+    }
+
+    /// Records the wall-clock duration of the traversal, given its start
+    /// time (as returned by `time::precise_time_s()`).
+    pub fn finish<E, D>(&mut self, _traversal: &D, start_time: f64)
+        where E: TElement,
+              D: DomTraversal<E>,
+    {
+        self.traversal_time_ms = (::time::precise_time_s() - start_time) * 1000.0;
+    }
+}
+
+impl<'a> Add for &'a TraversalStatistics {
+    type Output = TraversalStatistics;
+
+    fn add(self, other: &'a TraversalStatistics) -> TraversalStatistics {
+        TraversalStatistics {
+            elements_traversed: self.elements_traversed + other.elements_traversed,
+            elements_styled: self.elements_styled + other.elements_styled,
+            sharing_cache_hits: self.sharing_cache_hits + other.sharing_cache_hits,
+            sharing_cache_misses: self.sharing_cache_misses + other.sharing_cache_misses,
+            traversal_time_ms: self.traversal_time_ms + other.traversal_time_ms,
+        }
+    }
+}
+
+impl fmt::Display for TraversalStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "Traversed {} elements, styled {} in {:.2}ms (sharing cache hit ratio {:.1}%)",
+                 self.elements_traversed,
+                 self.elements_styled,
+                 self.traversal_time_ms,
+                 self.sharing_cache_hit_ratio() * 100.0)
+    }
+}
+
+/// Scratch space a single worker thread keeps for the duration of a
+/// traversal. One of these lives in each `ScopedTLS` slot.
+pub struct ThreadLocalStyleContext<E: TElement> {
+    /// This thread's contribution to the traversal's aggregate statistics.
+    pub statistics: TraversalStatistics,
+    // `fn() -> E` rather than `E` itself: we don't actually own an `E`,
+    // we just need to tag this type with it, and unlike `E` the function
+    // pointer marker is always `Send`/`Sync` regardless of what `E` is
+    // (which matters since this type is shared across threads via
+    // `ScopedTLS`).
+    _marker: PhantomData<fn() -> E>,
+}
+
+impl<E: TElement> ThreadLocalStyleContext<E> {
+    /// Creates a new, empty thread-local context.
+    pub fn new(_shared: &SharedStyleContext) -> Self {
+        ThreadLocalStyleContext {
+            statistics: TraversalStatistics::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Records a style sharing cache hit for this thread.
+    pub fn record_sharing_cache_hit(&mut self) {
+        self.statistics.sharing_cache_hits += 1;
+    }
+
+    /// Records a style sharing cache miss for this thread.
+    pub fn record_sharing_cache_miss(&mut self) {
+        self.statistics.sharing_cache_misses += 1;
+    }
+}
+
+/// The context a traversal implementation is handed for each node it
+/// processes: the state shared across all threads, plus this thread's
+/// own scratch space.
+pub struct StyleContext<'a, E: TElement + 'a> {
+    /// The shared, DOM-independent state for this traversal.
+    pub shared: &'a SharedStyleContext,
+    /// This thread's local scratch space.
+    pub thread_local: &'a mut ThreadLocalStyleContext<E>,
+}
+
+/// A fixed-size array of per-worker-thread slots, indexed by
+/// `rayon::ThreadPool::current_thread_index`, so the parallel traversal
+/// can lazily create one `T` per thread without a `thread_local!` per
+/// use site.
+///
+/// This only makes sense to access from within a `pool.install(...)`
+/// call on the `pool` it was built from; accessing it from any other
+/// thread panics.
+pub struct ScopedTLS<'a, T: Send> {
+    pool: &'a rayon::ThreadPool,
+    slots: Vec<RefCell<Option<T>>>,
+}
+
+#[allow(unsafe_code)]
+unsafe impl<'a, T: Send> Sync for ScopedTLS<'a, T> {}
+
+impl<'a, T: Send> ScopedTLS<'a, T> {
+    /// Creates a new `ScopedTLS` with one slot per thread in `pool`.
+    pub fn new(pool: &'a rayon::ThreadPool) -> Self {
+        let slot_count = pool.current_num_threads();
+        ScopedTLS {
+            pool,
+            slots: (0..slot_count).map(|_| RefCell::new(None)).collect(),
+        }
+    }
+
+    /// Returns this thread's slot, running `init` to populate it first
+    /// if this is the first access from this thread during the scope.
+    pub fn ensure<F>(&self, init: F) -> RefMut<'_, T>
+        where F: FnOnce(&mut Option<T>),
+    {
+        let index = self.pool.current_thread_index()
+            .expect("ScopedTLS accessed from outside the pool it was created for");
+        let mut slot = self.slots[index].borrow_mut();
+        if slot.is_none() {
+            init(&mut *slot);
+        }
+        RefMut::map(slot, |s| s.as_mut().unwrap())
+    }
+
+    /// Returns every slot, including ones no thread ever touched.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once every thread that could be holding a
+    /// `RefMut` from `ensure` has finished doing so (i.e. after the
+    /// `rayon::scope` this `ScopedTLS` was built for has returned).
+    #[allow(unsafe_code)]
+    pub unsafe fn unsafe_get(&self) -> &[RefCell<Option<T>>] {
+        &self.slots
+    }
+}
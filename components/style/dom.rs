@@ -0,0 +1,104 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Traits abstracting over a generic DOM-like tree, so that `parallel`
+//! doesn't need to know about any concrete implementation (servo's
+//! script DOM, Gecko's node tree, etc).
+
+use parallel::DomParallelInfo;
+use std::fmt::Debug;
+
+/// An opaque handle that uniquely identifies a DOM node, usable as a
+/// comparison key without exposing any of the node's actual data.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct OpaqueNode(pub usize);
+
+/// A DOM-like node: either a `TElement` or some other kind of node
+/// (text, comment, etc). Deliberately not `Send`: ordinary style system
+/// code should not be able to accidentally share a node across threads.
+/// The parallel traversal crosses that boundary explicitly and unsafely
+/// via `SendNode`.
+pub trait TNode: Clone + Copy + Debug + PartialEq + Sized {
+    /// The concrete `TElement` implementation tied to this node type.
+    type ConcreteElement: TElement<ConcreteNode = Self>;
+
+    /// Returns an opaque handle uniquely identifying this node.
+    fn opaque(&self) -> OpaqueNode;
+
+    /// Returns the parent of this node, if any.
+    fn parent_node(&self) -> Option<Self>;
+
+    /// Returns this node as an element, if it is one.
+    fn as_element(&self) -> Option<Self::ConcreteElement>;
+
+    /// Returns the bookkeeping the parallel bottom-up traversal uses to
+    /// know when all of this node's children have been processed.
+    fn parallel_info(&self) -> &DomParallelInfo;
+}
+
+/// An element node: a `TNode` that carries style information.
+pub trait TElement: TNode<ConcreteElement = Self> {
+    /// The concrete node type this element is a kind of.
+    type ConcreteNode: TNode<ConcreteElement = Self>;
+
+    /// Returns this element as a node.
+    fn as_node(&self) -> Self::ConcreteNode;
+
+    /// Returns the depth of this element in the DOM (the root is 0),
+    /// used for breadth-first scheduling.
+    fn depth(&self) -> usize;
+
+    /// A cheap, stable key two elements can be compared by to guess
+    /// whether they're likely to share computed styles (e.g. derived
+    /// from local name plus class/id/attribute hashes).
+    fn style_sharing_key(&self) -> u64;
+}
+
+/// A wrapper that allows a `TNode` to be sent to other threads during
+/// the parallel traversal, even though `TNode` itself is not `Send`.
+///
+/// # Safety
+///
+/// This is sound only because the parallel traversal's own invariants
+/// guarantee a node is never concurrently accessed from two threads at
+/// once (see the module docs on `parallel`); `SendNode` itself does
+/// nothing to enforce that.
+#[derive(Debug)]
+pub struct SendNode<N: TNode>(N);
+
+#[allow(unsafe_code)]
+unsafe impl<N: TNode> Send for SendNode<N> {}
+
+// Also `Sync`, for the same reason: work units get passed around behind a
+// shared reference when Rayon captures them into a `scope` closure, even
+// though only one thread ever actually touches a given node at a time.
+#[allow(unsafe_code)]
+unsafe impl<N: TNode> Sync for SendNode<N> {}
+
+impl<N: TNode> SendNode<N> {
+    /// Creates a new `SendNode`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the wrapped node is not concurrently
+    /// accessed from another thread while this `SendNode` (or any clone
+    /// of it) is alive.
+    #[allow(unsafe_code)]
+    pub unsafe fn new(node: N) -> Self {
+        SendNode(node)
+    }
+}
+
+impl<N: TNode> Clone for SendNode<N> {
+    fn clone(&self) -> Self {
+        SendNode(self.0)
+    }
+}
+
+impl<N: TNode> ::std::ops::Deref for SendNode<N> {
+    type Target = N;
+    fn deref(&self) -> &N {
+        &self.0
+    }
+}
@@ -0,0 +1,22 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The style crate computes styles for a DOM-like tree, sequentially or
+//! in parallel (see `parallel`).
+
+#![deny(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate arrayvec;
+#[cfg(feature = "servo")]
+extern crate crossbeam_queue;
+extern crate rayon;
+extern crate smallvec;
+extern crate stacker;
+extern crate time;
+
+pub mod context;
+pub mod dom;
+pub mod parallel;
+pub mod traversal;
@@ -23,32 +23,198 @@
 #![deny(missing_docs)]
 
 use arrayvec::ArrayVec;
-use context::{StyleContext, ThreadLocalStyleContext, TraversalStatistics};
+use context::{ScopedTLS, StyleContext, ThreadLocalStyleContext, TraversalStatistics};
 use dom::{OpaqueNode, SendNode, TElement, TNode};
 use rayon;
-use scoped_tls::ScopedTLS;
 use smallvec::SmallVec;
+use stacker;
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::sync::atomic::{self, AtomicIsize, Ordering};
+use std::sync::RwLock;
 use time;
 use traversal::{DomTraversal, PerLevelTraversalData, PreTraverseToken};
 
-/// The maximum number of child nodes that we will process as a single unit.
+#[cfg(feature = "servo")]
+use crossbeam_queue::SegQueue;
+#[cfg(not(feature = "servo"))]
+use self::segqueue_shim::SegQueue;
+
+// gecko doesn't currently pull in crossbeam-queue; fall back to a tiny
+// Mutex-protected VecDeque with the same interface we need so the scheduler
+// below doesn't have to care which backend it's built on.
+#[cfg(not(feature = "servo"))]
+mod segqueue_shim {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Mirrors `crossbeam_queue::PopError`, returned when the queue has
+    /// nothing to pop right now.
+    pub struct PopError;
+
+    pub struct SegQueue<T>(Mutex<VecDeque<T>>);
+
+    impl<T> SegQueue<T> {
+        pub fn new() -> Self {
+            SegQueue(Mutex::new(VecDeque::new()))
+        }
+
+        pub fn push(&self, value: T) {
+            self.0.lock().unwrap().push_back(value);
+        }
+
+        pub fn pop(&self) -> Result<T, PopError> {
+            self.0.lock().unwrap().pop_front().ok_or(PopError)
+        }
+    }
+}
+
+/// The default number of child nodes that we will process as a single unit.
 ///
 /// Larger values will increase style sharing cache hits and general DOM
 /// locality at the expense of decreased opportunities for parallelism.  There
 /// are some measurements in
 /// https://bugzilla.mozilla.org/show_bug.cgi?id=1385982#c11 and comments 12
 /// and 13 that investigate some slightly different values for the work unit
-/// size.  If the size is significantly increased, make sure to adjust the
-/// condition for kicking off a new work unit in top_down_dom, because
-/// otherwise we're likely to end up doing too much work serially.  For
-/// example, the condition there could become some fraction of WORK_UNIT_MAX
-/// instead of WORK_UNIT_MAX.
+/// size.
+///
+/// This is only the starting point now: `top_down_dom` adapts its effective
+/// flush threshold at runtime between `WORK_UNIT_MIN` and `WORK_UNIT_MAX_CAP`
+/// based on how well the style sharing cache is doing (see
+/// `adaptive_flush_threshold`), and dispatches in chunks of that same
+/// threshold. `WORK_UNIT_MAX` itself is still used as the chunk size for the
+/// root of `traverse_dom` and throughout the bottom-up traversal.
 pub const WORK_UNIT_MAX: usize = 16;
 
+/// The smallest flush threshold `top_down_dom` will adapt down to when
+/// threads are starving for work. Small work units discover parallelism
+/// fastest, at the cost of style sharing across cousins.
+pub const WORK_UNIT_MIN: usize = 4;
+
+/// The largest flush threshold `top_down_dom` will adapt up to when the
+/// style sharing cache is running hot. This is the bound `WorkUnit` is
+/// actually sized to, since the adaptive threshold can reach it even though
+/// `WORK_UNIT_MAX` is the common case.
+pub const WORK_UNIT_MAX_CAP: usize = 64;
+
 /// A set of nodes, sized to the work unit. This gets copied when sent to other
 /// threads, so we keep it compact.
-type WorkUnit<N> = ArrayVec<[SendNode<N>; WORK_UNIT_MAX]>;
+type WorkUnit<N> = ArrayVec<[SendNode<N>; WORK_UNIT_MAX_CAP]>;
+
+/// A pending unit of top-down work: the nodes to process, and the
+/// traversal-level data (e.g. DOM depth) that goes along with them.
+type ScheduledWork<N> = (WorkUnit<N>, PerLevelTraversalData);
+
+/// Crate-internal depth-ordered scheduling for the top-down traversal.
+///
+/// The breadth-first guarantee that makes the style sharing cache effective
+/// used to rely entirely on the caller configuring the whole Rayon pool with
+/// `.breadth_first()` (a FIFO work queue). That's fragile: a pool that wasn't
+/// built that way silently degrades sharing. Instead, we keep one queue of
+/// pending work per DOM depth here and always drain the lowest non-empty
+/// depth, so `top_down_dom` gets correct breadth-first behavior regardless of
+/// how `pool` was constructed.
+///
+/// Queues are created lazily as depths are actually seen, rather than
+/// pre-allocated for some assumed maximum DOM depth: most documents never
+/// come close to using more than a handful of levels at once, and the
+/// recursive tail-call chain itself is bounded by measured stack headroom
+/// (see `has_tail_call_headroom`), not a fixed depth.
+struct DepthScheduler<N: TNode> {
+    queues: RwLock<Vec<SegQueue<ScheduledWork<N>>>>,
+}
+
+impl<N: TNode> DepthScheduler<N> {
+    fn new() -> Self {
+        DepthScheduler {
+            queues: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Grows `queues` so that index `depth` exists, if it doesn't already.
+    fn ensure_depth(&self, depth: usize) {
+        if depth < self.queues.read().unwrap().len() {
+            return;
+        }
+        let mut queues = self.queues.write().unwrap();
+        while queues.len() <= depth {
+            queues.push(SegQueue::new());
+        }
+    }
+
+    /// Enqueues a work unit for the depth recorded in `traversal_data`.
+    fn push(&self, work: WorkUnit<N>, traversal_data: PerLevelTraversalData) {
+        let depth = traversal_data.current_dom_depth;
+        self.ensure_depth(depth);
+        self.queues.read().unwrap()[depth].push((work, traversal_data));
+    }
+
+    /// Pops a work unit from the lowest depth that currently has one.
+    ///
+    /// We always rescan from depth 0 rather than remembering a "floor" to
+    /// resume from: a floor that skips past depths found momentarily empty
+    /// can race with a concurrent push landing at exactly that depth, and
+    /// permanently strand the pushed item below the floor where nothing
+    /// will ever look again. Depth ranges are shallow in practice (bounded
+    /// by actual DOM depth), so the rescan is cheap.
+    fn pop(&self) -> Option<ScheduledWork<N>> {
+        let queues = self.queues.read().unwrap();
+        for queue in queues.iter() {
+            if let Ok(item) = queue.pop() {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+/// Per-node bookkeeping used by the parallel bottom-up traversal.
+///
+/// A preorder pass populates `children_count` with the number of children a
+/// node has still to be processed. Each time a child finishes, it decrements
+/// its parent's counter; the thread that drives it to zero is the one that
+/// knows every child has been handled, and so becomes responsible for
+/// processing the parent. This mirrors the breadth-first work-stealing story
+/// of `top_down_dom`, just running in the opposite direction.
+pub struct DomParallelInfo {
+    children_count: AtomicIsize,
+}
+
+impl DomParallelInfo {
+    /// Creates a new `DomParallelInfo` with the given number of children
+    /// still pending.
+    pub fn new(children_count: isize) -> Self {
+        DomParallelInfo {
+            children_count: AtomicIsize::new(children_count),
+        }
+    }
+
+    /// (Re)initializes the pending-children counter ahead of a bottom-up
+    /// pass, publishing it with `Release` ordering so that whichever
+    /// thread later decrements it to zero is guaranteed to observe this
+    /// store (and everything that happened-before it).
+    fn reset(&self, children_count: isize) {
+        self.children_count.store(children_count, Ordering::Release);
+    }
+
+    /// Records that one of this node's children has finished processing,
+    /// returning `true` if the caller observed the *last* child (and is
+    /// thus responsible for processing this node next).
+    ///
+    /// The decrement uses `Release` ordering so the results this thread
+    /// just computed for the child become visible to whichever thread
+    /// drives the count to zero; that thread follows up with an `Acquire`
+    /// fence, which -- because the decrements form a release sequence on
+    /// `children_count` -- synchronizes with every decrement that came
+    /// before it, not just this one.
+    fn note_child_processed(&self) -> bool {
+        let saw_last_child = self.children_count.fetch_sub(1, Ordering::Release) == 1;
+        if saw_last_child {
+            atomic::fence(Ordering::Acquire);
+        }
+        saw_last_child
+    }
+}
 
 /// Entry point for the parallel traversal.
 #[allow(unsafe_code)]
@@ -69,6 +235,7 @@ pub fn traverse_dom<E, D>(traversal: &D,
         current_dom_depth: root.depth(),
     };
     let tls = ScopedTLS::<ThreadLocalStyleContext<E>>::new(pool);
+    let scheduler = DepthScheduler::<E::ConcreteNode>::new();
     let send_root = unsafe { SendNode::new(root.as_node()) };
 
     pool.install(|| {
@@ -77,13 +244,14 @@ pub fn traverse_dom<E, D>(traversal: &D,
             let root_opaque = root.opaque();
             traverse_nodes(&[root],
                            DispatchMode::TailCall,
-                           0,
+                           WORK_UNIT_MAX,
                            root_opaque,
                            traversal_data,
                            scope,
                            pool,
                            traversal,
-                           &tls);
+                           &tls,
+                           &scheduler);
         });
     });
 
@@ -103,6 +271,45 @@ pub fn traverse_dom<E, D>(traversal: &D,
     }
 }
 
+/// Entry point for the parallel bottom-up (post-order) traversal.
+///
+/// This assumes a preorder pass has already run over the tree (for example
+/// `traverse_dom`) and left every node's `DomParallelInfo::children_count`
+/// set to its number of children. `leaves` are the nodes with no children at
+/// all, which are the only ones ready to run immediately; everything else is
+/// discovered as the traversal climbs back up.
+#[allow(unsafe_code)]
+pub fn traverse_dom_bottom_up<E, D>(traversal: &D,
+                                    root: E,
+                                    leaves: &[E::ConcreteNode],
+                                    pool: &rayon::ThreadPool)
+    where E: TElement,
+          D: DomTraversal<E>,
+{
+    debug_assert!(traversal.is_parallel());
+
+    let tls = ScopedTLS::<ThreadLocalStyleContext<E>>::new(pool);
+    let root_opaque = unsafe { SendNode::new(root.as_node()) }.opaque();
+    let send_leaves = leaves.iter()
+        .map(|&n| unsafe { SendNode::new(n) })
+        .collect::<SmallVec<[SendNode<E::ConcreteNode>; 128]>>();
+
+    pool.install(|| {
+        rayon::scope(|scope| {
+            for chunk in send_leaves.chunks(WORK_UNIT_MAX) {
+                let work = chunk.iter().cloned().collect::<WorkUnit<E::ConcreteNode>>();
+                traverse_nodes_bottom_up(&work,
+                                        DispatchMode::TailCall,
+                                        root_opaque,
+                                        scope,
+                                        pool,
+                                        traversal,
+                                        &tls);
+            }
+        });
+    });
+}
+
 /// A callback to create our thread local context.  This needs to be
 /// out of line so we don't allocate stack space for the entire struct
 /// in the caller.
@@ -116,6 +323,86 @@ fn create_thread_local_context<'scope, E, D>(
     *slot = Some(ThreadLocalStyleContext::new(traversal.shared_context()));
 }
 
+/// Picks the flush threshold `top_down_dom` should use for this call,
+/// somewhere between `WORK_UNIT_MIN` and `WORK_UNIT_MAX_CAP`.
+///
+/// If the pool looks starved for work on this thread, we shrink the
+/// threshold toward `WORK_UNIT_MIN` so we discover parallelism sooner rather
+/// than holding onto a big batch for sharing's sake. Otherwise, we grow it
+/// toward `WORK_UNIT_MAX_CAP` when this thread's style sharing cache has
+/// been hitting often, since bigger work units mean more cousins get styled
+/// in sequence on the same thread. `WORK_UNIT_MAX` is the fallback when
+/// neither signal is strong enough to move us off the historical default.
+fn adaptive_flush_threshold<E>(thread_local: &ThreadLocalStyleContext<E>,
+                               pool: &rayon::ThreadPool)
+                               -> usize
+    where E: TElement,
+{
+    let starved = !pool.current_thread_has_pending_tasks().unwrap_or(true);
+    if starved {
+        return WORK_UNIT_MIN;
+    }
+
+    if thread_local.statistics.sharing_cache_hit_ratio() > 0.75 {
+        WORK_UNIT_MAX_CAP
+    } else {
+        WORK_UNIT_MAX
+    }
+}
+
+/// Reorders `nodes` so that cousins sharing a cheap style-sharing key end up
+/// adjacent, instead of being split apart purely by document order.
+///
+/// `discovered_child_nodes` accumulates the combined children of up to
+/// `flush_threshold` parents before flushing, so cousins that would share
+/// style often get handed to different work units (and likely different
+/// threads) just because of where they happened to fall in that buffer.
+/// Grouping by key raises the odds a single thread styles a run of similar
+/// cousins back to back, which is what the thread-local sharing cache
+/// actually benefits from. Nodes that don't share a key with anything else
+/// in the buffer (the long tail) keep their original relative order.
+///
+/// This only reorders nodes *within* a single flushed buffer, all of which
+/// sit at the same DOM depth, so it doesn't disturb the breadth-first depth invariant
+/// `DepthScheduler` relies on. Gated behind
+/// `StyleSystemOptions::regroup_cousins_by_sharing_key` so it can be A/B
+/// measured via the existing `dump_style_statistics` path.
+fn regroup_by_sharing_key<N>(nodes: &mut SmallVec<[SendNode<N>; 128]>)
+    where N: TNode,
+{
+    // The key is whatever cheap signal the style sharing cache itself
+    // already keys on (local name + class/id hash + relevant attributes);
+    // non-elements (e.g. text nodes) have no such key and just keep their
+    // own slot.
+    let mut first_seen_rank = HashMap::new();
+    let mut next_rank = 0usize;
+    let ranks = nodes.iter().map(|n| {
+        let key = (**n).as_element().map(|el| el.style_sharing_key());
+        match key {
+            None => {
+                let rank = next_rank;
+                next_rank += 1;
+                rank
+            }
+            Some(key) => {
+                *first_seen_rank.entry(key).or_insert_with(|| {
+                    let rank = next_rank;
+                    next_rank += 1;
+                    rank
+                })
+            }
+        }
+    }).collect::<Vec<usize>>();
+
+    let mut order = (0..nodes.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&i| ranks[i]);
+
+    let regrouped = order.iter()
+        .map(|&i| nodes[i].clone())
+        .collect::<SmallVec<[SendNode<N>; 128]>>();
+    *nodes = regrouped;
+}
+
 /// A parallel top-down DOM traversal.
 ///
 /// This algorithm traverses the DOM in a breadth-first, top-down manner. The
@@ -131,24 +418,37 @@ fn create_thread_local_context<'scope, E, D>(
 ///   a thread-local cache to share styles between siblings.
 #[inline(always)]
 #[allow(unsafe_code)]
+#[allow(clippy::too_many_arguments)]
 fn top_down_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
-                                  recursion_depth: usize,
                                   root: OpaqueNode,
                                   mut traversal_data: PerLevelTraversalData,
                                   scope: &'a rayon::Scope<'scope>,
                                   pool: &'scope rayon::ThreadPool,
                                   traversal: &'scope D,
-                                  tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>)
+                                  tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>,
+                                  scheduler: &'scope DepthScheduler<E::ConcreteNode>)
     where E: TElement + 'scope,
           D: DomTraversal<E>,
 {
-    debug_assert!(nodes.len() <= WORK_UNIT_MAX);
+    debug_assert!(nodes.len() <= WORK_UNIT_MAX_CAP);
 
     // Collect all the children of the elements in our work unit. This will
-    // contain the combined children of up to WORK_UNIT_MAX nodes, which may
-    // be numerous. As such, we store it in a large SmallVec to minimize heap-
-    // spilling, and never move it.
+    // contain the combined children of up to WORK_UNIT_MAX_CAP nodes, which
+    // may be numerous. As such, we store it in a large SmallVec to minimize
+    // heap-spilling, and never move it.
     let mut discovered_child_nodes = SmallVec::<[SendNode<E::ConcreteNode>; 128]>::new();
+
+    // Decide how big a work unit we're willing to accumulate before
+    // flushing, based on how this thread's style sharing cache has been
+    // doing and on whether the pool looks starved for work. We only read
+    // this once per call: re-checking mid-loop would make the sharing
+    // behavior of a single work unit depend on exactly where we are in it,
+    // which isn't worth the complexity. We also reuse it below as the
+    // dispatch chunk size, so that flushing a big batch to keep cousins
+    // together isn't immediately undone by re-splitting it into smaller
+    // `WORK_UNIT_MAX`-sized work units on separate threads.
+    let flush_threshold;
+
     {
         // Scope the borrow of the TLS so that the borrow is dropped before
         // a potential recursive call when we pass TailCall.
@@ -159,6 +459,8 @@ fn top_down_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
             thread_local: &mut *tlc,
         };
 
+        flush_threshold = adaptive_flush_threshold(&*context.thread_local, pool);
+
         for n in nodes {
             // If the last node we processed produced children, we may want to
             // spawn them off into a work item. We do this at the beginning of
@@ -197,18 +499,22 @@ fn top_down_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
             // traversal as soon as we discovered kids, we would instead
             // process such a tree more or less with a thread-per-branch,
             // multiplexed across our actual threadpool.
-            if discovered_child_nodes.len() >= WORK_UNIT_MAX {
+            if discovered_child_nodes.len() >= flush_threshold {
+                if traversal.shared_context().options.regroup_cousins_by_sharing_key {
+                    regroup_by_sharing_key(&mut discovered_child_nodes);
+                }
                 let mut traversal_data_copy = traversal_data.clone();
                 traversal_data_copy.current_dom_depth += 1;
-                traverse_nodes(&*discovered_child_nodes,
+                traverse_nodes(&discovered_child_nodes,
                                DispatchMode::NotTailCall,
-                               recursion_depth,
+                               flush_threshold,
                                root,
                                traversal_data_copy,
                                scope,
                                pool,
                                traversal,
-                               tls);
+                               tls,
+                               scheduler);
                 discovered_child_nodes.clear();
             }
 
@@ -220,6 +526,12 @@ fn top_down_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
                 discovered_child_nodes.push(send_n);
             });
 
+            // Prime this node's pending-children counter so that a later
+            // `traverse_dom_bottom_up` pass knows when it's safe to climb
+            // back up to this node's parent. This is harmless busywork if
+            // no bottom-up pass ever follows.
+            node.parallel_info().reset(children_to_process);
+
             traversal.handle_postorder_traversal(&mut context, root, node,
                                                  children_to_process);
         }
@@ -229,16 +541,94 @@ fn top_down_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
     // for yet.  If any exist, we can process them (or at least one work unit's
     // worth of them) directly on this thread by passing TailCall.
     if !discovered_child_nodes.is_empty() {
+        if traversal.shared_context().options.regroup_cousins_by_sharing_key {
+            regroup_by_sharing_key(&mut discovered_child_nodes);
+        }
         traversal_data.current_dom_depth += 1;
         traverse_nodes(&discovered_child_nodes,
                        DispatchMode::TailCall,
-                       recursion_depth,
+                       flush_threshold,
                        root,
                        traversal_data,
                        scope,
                        pool,
                        traversal,
-                       tls);
+                       tls,
+                       scheduler);
+    }
+}
+
+/// A parallel bottom-up (post-order) DOM traversal, driven by the
+/// pending-children counters that `DomParallelInfo` tracks.
+///
+/// Mirrors `top_down_dom`: nodes in `nodes` are known to be ready (either
+/// because they're leaves or because the last of their children just
+/// finished), so we process them directly and then try to make their
+/// parents ready in turn. Parents discovered this way are batched into
+/// `discovered_parent_nodes` up to `WORK_UNIT_MAX` before being flushed,
+/// for the same sharing/locality reasons `top_down_dom` batches children.
+#[inline(always)]
+#[allow(unsafe_code)]
+fn bottom_up_dom<'a, 'scope, E, D>(nodes: &'a [SendNode<E::ConcreteNode>],
+                                   root: OpaqueNode,
+                                   scope: &'a rayon::Scope<'scope>,
+                                   pool: &'scope rayon::ThreadPool,
+                                   traversal: &'scope D,
+                                   tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>)
+    where E: TElement + 'scope,
+          D: DomTraversal<E>,
+{
+    debug_assert!(nodes.len() <= WORK_UNIT_MAX);
+
+    let mut discovered_parent_nodes = SmallVec::<[SendNode<E::ConcreteNode>; 128]>::new();
+    {
+        let mut tlc = tls.ensure(
+            |slot: &mut Option<ThreadLocalStyleContext<E>>| create_thread_local_context(traversal, slot));
+        let mut context = StyleContext {
+            shared: traversal.shared_context(),
+            thread_local: &mut *tlc,
+        };
+
+        for n in nodes {
+            if discovered_parent_nodes.len() >= WORK_UNIT_MAX {
+                traverse_nodes_bottom_up(&discovered_parent_nodes,
+                                         DispatchMode::NotTailCall,
+                                         root,
+                                         scope,
+                                         pool,
+                                         traversal,
+                                         tls);
+                discovered_parent_nodes.clear();
+            }
+
+            let node = **n;
+            traversal.process_postorder(&mut context, node);
+
+            // Climb to the parent and see if we're the last child to finish.
+            // If so, the parent is now ready to be processed as a unit of
+            // its own; otherwise some sibling is still outstanding and will
+            // be the one to pick it up.
+            if node.opaque() == root {
+                continue;
+            }
+            if let Some(parent) = node.parent_node() {
+                let was_last_child = parent.parallel_info().note_child_processed();
+                if was_last_child {
+                    let send_parent = unsafe { SendNode::new(parent) };
+                    discovered_parent_nodes.push(send_parent);
+                }
+            }
+        }
+    }
+
+    if !discovered_parent_nodes.is_empty() {
+        traverse_nodes_bottom_up(&discovered_parent_nodes,
+                                 DispatchMode::TailCall,
+                                 root,
+                                 scope,
+                                 pool,
+                                 traversal,
+                                 tls);
     }
 }
 
@@ -254,26 +644,53 @@ impl DispatchMode {
     fn is_tail_call(&self) -> bool { matches!(*self, DispatchMode::TailCall) }
 }
 
-// On x86_64-linux, a recursive cycle requires 3472 bytes of stack.  Limiting
-// the depth to 150 therefore should keep the stack use by the recursion to
-// 520800 bytes, which would give a generously conservative margin should we
-// decide to reduce the thread stack size from its default of 2MB down to 1MB.
-const RECURSION_DEPTH_LIMIT: usize = 150;
+// On x86_64-linux, a recursive cycle requires roughly 3472 bytes of stack.
+// We used to cap recursion at a flat depth of 150 derived from that number
+// against an assumed 1MB worst-case stack, which is safe but wastes tail-call
+// opportunities on the 2MB+ stacks most platforms actually hand worker
+// threads, and would quietly become unsafe again if frame sizes grew. We
+// measure actual remaining stack instead (see `has_tail_call_headroom`
+// below) and only keep recursing while there's room for several more cycles
+// beyond this per-frame estimate.
+const BYTES_PER_RECURSION_CYCLE: usize = 3472;
+
+/// How many more recursion cycles worth of headroom we insist on keeping in
+/// reserve before giving up on tail recursion and falling back to
+/// `scope.spawn`. Deliberately generous: `process_preorder`/
+/// `process_postorder` can use stack space beyond what we account for here
+/// (e.g. deeply nested selector matching), so we don't want to cut this
+/// razor-thin.
+const RECURSION_HEADROOM_CYCLES: usize = 30;
+
+/// Whether there's enough measured stack headroom left on this thread to
+/// safely make another recursive tail call into `top_down_dom` or
+/// `bottom_up_dom`. If we can't get a reading at all, we play it safe and
+/// say no -- that just means we dispatch via `scope.spawn` instead, which is
+/// always correct, just slower.
+fn has_tail_call_headroom() -> bool {
+    match stacker::remaining_stack() {
+        Some(remaining) => remaining > BYTES_PER_RECURSION_CYCLE * RECURSION_HEADROOM_CYCLES,
+        None => false,
+    }
+}
 
 #[inline]
+#[allow(clippy::too_many_arguments)]
 fn traverse_nodes<'a, 'scope, E, D>(nodes: &[SendNode<E::ConcreteNode>],
                                     mode: DispatchMode,
-                                    recursion_depth: usize,
+                                    dispatch_chunk_size: usize,
                                     root: OpaqueNode,
                                     traversal_data: PerLevelTraversalData,
                                     scope: &'a rayon::Scope<'scope>,
                                     pool: &'scope rayon::ThreadPool,
                                     traversal: &'scope D,
-                                    tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>)
+                                    tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>,
+                                    scheduler: &'scope DepthScheduler<E::ConcreteNode>)
     where E: TElement + 'scope,
           D: DomTraversal<E>,
 {
     debug_assert!(!nodes.is_empty());
+    debug_assert!((WORK_UNIT_MIN..=WORK_UNIT_MAX_CAP).contains(&dispatch_chunk_size));
 
     // This is a tail call from the perspective of the caller. However, we only
     // want to actually dispatch the job as a tail call if there's nothing left
@@ -282,33 +699,93 @@ fn traverse_nodes<'a, 'scope, E, D>(nodes: &[SendNode<E::ConcreteNode>],
     // overflow due to excessive tail recursion. The stack overflow isn't
     // observable to content -- we're still completely correct, just not
     // using tail recursion any more. See bug 1368302.
-    debug_assert!(recursion_depth <= RECURSION_DEPTH_LIMIT);
     let may_dispatch_tail = mode.is_tail_call() &&
-        recursion_depth != RECURSION_DEPTH_LIMIT &&
+        has_tail_call_headroom() &&
         !pool.current_thread_has_pending_tasks().unwrap();
 
+    // We hand our work off to `scheduler` rather than carrying it forward
+    // directly: the next unit a thread picks up should be whichever is at
+    // the lowest outstanding DOM depth *globally*, not necessarily the one we
+    // just produced. That's what gives us breadth-first ordering (and thus
+    // effective style sharing) no matter how `pool` itself schedules tasks.
+    //
+    // `dispatch_chunk_size` is `WORK_UNIT_MAX` for the root call from
+    // `traverse_dom`, but `top_down_dom` passes its own adaptive flush
+    // threshold here instead: dispatching in
+    // chunks smaller than what we just flushed would just split a buffer we
+    // deliberately grew to keep cousins together back apart again, across
+    // however many work units (and threads) `nodes.len() / WORK_UNIT_MAX`
+    // happens to be.
+    //
     // In the common case, our children fit within a single work unit, in which
     // case we can pass the SmallVec directly and avoid extra allocation.
+    if nodes.len() <= dispatch_chunk_size {
+        let work = nodes.iter().cloned().collect::<WorkUnit<E::ConcreteNode>>();
+        scheduler.push(work, traversal_data);
+        if may_dispatch_tail {
+            if let Some((work, traversal_data)) = scheduler.pop() {
+                top_down_dom(&work, root,
+                             traversal_data, scope, pool, traversal, tls, scheduler);
+            }
+        } else {
+            scope.spawn(move |scope| {
+                if let Some((work, traversal_data)) = scheduler.pop() {
+                    top_down_dom(&work, root,
+                                 traversal_data, scope, pool, traversal, tls, scheduler);
+                }
+            });
+        }
+    } else {
+        for chunk in nodes.chunks(dispatch_chunk_size) {
+            let work = chunk.iter().cloned().collect::<WorkUnit<E::ConcreteNode>>();
+            scheduler.push(work, traversal_data.clone());
+            scope.spawn(move |scope| {
+                if let Some((work, traversal_data)) = scheduler.pop() {
+                    top_down_dom(&work, root,
+                                 traversal_data, scope, pool, traversal, tls, scheduler)
+                }
+            });
+        }
+    }
+}
+
+/// Same dispatch logic as `traverse_nodes`, but for `bottom_up_dom`. There's
+/// no `PerLevelTraversalData` to thread through here, since the bottom-up
+/// pass doesn't need a notion of current depth -- readiness is driven
+/// entirely by `DomParallelInfo::children_count` reaching zero.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn traverse_nodes_bottom_up<'a, 'scope, E, D>(nodes: &[SendNode<E::ConcreteNode>],
+                                              mode: DispatchMode,
+                                              root: OpaqueNode,
+                                              scope: &'a rayon::Scope<'scope>,
+                                              pool: &'scope rayon::ThreadPool,
+                                              traversal: &'scope D,
+                                              tls: &'scope ScopedTLS<'scope, ThreadLocalStyleContext<E>>)
+    where E: TElement + 'scope,
+          D: DomTraversal<E>,
+{
+    debug_assert!(!nodes.is_empty());
+    let may_dispatch_tail = mode.is_tail_call() &&
+        has_tail_call_headroom() &&
+        !pool.current_thread_has_pending_tasks().unwrap();
+
     if nodes.len() <= WORK_UNIT_MAX {
         let work = nodes.iter().cloned().collect::<WorkUnit<E::ConcreteNode>>();
         if may_dispatch_tail {
-            top_down_dom(&work, recursion_depth + 1, root,
-                         traversal_data, scope, pool, traversal, tls);
+            bottom_up_dom(&work, root, scope, pool, traversal, tls);
         } else {
             scope.spawn(move |scope| {
                 let work = work;
-                top_down_dom(&work, 0, root,
-                             traversal_data, scope, pool, traversal, tls);
+                bottom_up_dom(&work, root, scope, pool, traversal, tls);
             });
         }
     } else {
         for chunk in nodes.chunks(WORK_UNIT_MAX) {
             let nodes = chunk.iter().cloned().collect::<WorkUnit<E::ConcreteNode>>();
-            let traversal_data_copy = traversal_data.clone();
             scope.spawn(move |scope| {
                 let n = nodes;
-                top_down_dom(&*n, 0, root,
-                             traversal_data_copy, scope, pool, traversal, tls)
+                bottom_up_dom(&n, root, scope, pool, traversal, tls)
             });
         }
     }
@@ -0,0 +1,65 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Traits and helper types that drive a DOM traversal, independent of
+//! whether it runs sequentially or in parallel.
+
+use context::{SharedStyleContext, StyleContext};
+use dom::{OpaqueNode, TElement};
+
+/// Data that the traversal threads through each level of its recursion,
+/// describing the nodes currently being processed rather than any one
+/// node in particular.
+#[derive(Clone)]
+pub struct PerLevelTraversalData {
+    /// The current depth in the DOM of the nodes being processed.
+    pub current_dom_depth: usize,
+}
+
+/// The result of a traversal's pre-traverse step: whether there's
+/// actually any work to do.
+pub struct PreTraverseToken {
+    should_traverse: bool,
+}
+
+impl PreTraverseToken {
+    /// Creates a new token.
+    pub fn new(should_traverse: bool) -> Self {
+        PreTraverseToken { should_traverse }
+    }
+
+    /// Whether the traversal should actually run.
+    pub fn should_traverse(&self) -> bool {
+        self.should_traverse
+    }
+}
+
+/// A DOM traversal, implementable either sequentially or in parallel.
+pub trait DomTraversal<E: TElement>: Sync {
+    /// Processes `node` in preorder, invoking `note_child` for each of
+    /// its children so the caller can build up the next level of work.
+    fn process_preorder<F>(&self,
+                           traversal_data: &PerLevelTraversalData,
+                           context: &mut StyleContext<E>,
+                           node: E::ConcreteNode,
+                           note_child: F)
+        where F: FnMut(E::ConcreteNode);
+
+    /// Runs once all of `node`'s children have been discovered (not
+    /// necessarily processed yet), with the number of children found.
+    fn handle_postorder_traversal(&self,
+                                  context: &mut StyleContext<E>,
+                                  root: OpaqueNode,
+                                  node: E::ConcreteNode,
+                                  children_to_process: isize);
+
+    /// Processes `node` in postorder, as part of the bottom-up pass.
+    fn process_postorder(&self, context: &mut StyleContext<E>, node: E::ConcreteNode);
+
+    /// Whether this traversal is running in parallel.
+    fn is_parallel(&self) -> bool;
+
+    /// The DOM-independent state shared by every thread in the traversal.
+    fn shared_context(&self) -> &SharedStyleContext;
+}